@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while loading, compiling or running a bf
+/// script, in place of the `unwrap()`/`panic!` calls this crate used to have.
+#[derive(Debug)]
+pub enum BfError {
+    FileNotFound(String),
+    Regex(regex::Error),
+    UnbalancedBracket { pos: usize },
+    InvalidAddress(isize),
+    UnknownInstruction(char),
+    TapeLimitExceeded { needed: usize, max: usize },
+    InvalidCellBits(u8),
+    BytecodeInvalid(String),
+    BytecodeLevelMismatch { expected: usize, found: usize },
+    BytecodeStaleSource,
+    Io(io::Error),
+}
+
+impl fmt::Display for BfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BfError::FileNotFound(path) => write!(f, "cannot find {}", path),
+            BfError::Regex(e) => write!(f, "invalid regex: {}", e),
+            BfError::UnbalancedBracket { pos } => write!(f, "unmatched bracket at byte {}", pos),
+            BfError::InvalidAddress(addr) => write!(f, "invalid address! {}", addr),
+            BfError::UnknownInstruction(c) => write!(f, "unknown instruction: {:?}", c),
+            BfError::TapeLimitExceeded { needed, max } => {
+                write!(f, "tape would grow to {} cells, exceeding --max-tape of {}", needed, max)
+            },
+            BfError::InvalidCellBits(bits) => write!(f, "invalid --cell-bits {}: expected 8, 16, or 32", bits),
+            BfError::BytecodeInvalid(reason) => write!(f, "invalid bytecode file: {}", reason),
+            BfError::BytecodeLevelMismatch { expected, found } => {
+                write!(f, "bytecode was compiled at optimization level {}, but level {} was requested", found, expected)
+            },
+            BfError::BytecodeStaleSource => write!(f, "bytecode does not match the current source file"),
+            BfError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BfError::Regex(e) => Some(e),
+            BfError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BfError {
+    fn from(e: io::Error) -> Self {
+        BfError::Io(e)
+    }
+}
+
+impl From<regex::Error> for BfError {
+    fn from(e: regex::Error) -> Self {
+        BfError::Regex(e)
+    }
+}
@@ -1,7 +1,10 @@
+mod error;
+
 use argh::FromArgs;
+use error::BfError;
 use num_format::{Locale, ToFormattedString};
 use regex::{Regex, Captures};
-use std::{io::{Read, self, Write}, fs, time::Instant};
+use std::{collections::HashSet, io::{Read, self, Write}, fs, time::{Duration, Instant}};
 
 #[derive(FromArgs)]
 #[argh(description="arguments")]
@@ -42,21 +45,163 @@ struct Args {
         switch,
     )]
     flush_immediate: bool,
+    #[argh(
+        default="TAPE_CHUNK",
+        description="initial tape size in cells",
+        option,
+    )]
+    tape_size: usize,
+    #[argh(
+        description="maximum tape size in cells; growing past this aborts with an error",
+        option,
+    )]
+    max_tape: Option<usize>,
+    #[argh(
+        description="drop into an interactive stepping debugger instead of running straight through",
+        switch,
+    )]
+    interactive: bool,
+    #[argh(
+        description="serialize the compiled instruction stream to this file instead of running it",
+        option,
+    )]
+    emit: Option<String>,
+    #[argh(
+        description="load a bytecode file written by --emit and run it directly, skipping parsing and optimization",
+        option,
+    )]
+    run_bytecode: Option<String>,
+    #[argh(
+        default="8",
+        description="cell width in bits: 8, 16, or 32",
+        option,
+    )]
+    cell_bits: u8,
+    #[argh(
+        default="EofMode::Zero",
+        description="what StdIn writes when the input queue and stdin are both exhausted: zero, neg-one, or unchanged",
+        option,
+    )]
+    eof_mode: EofMode,
+}
+
+/// What `StdIn` leaves in the current cell once the input queue and stdin
+/// are both exhausted.
+#[derive(Debug,PartialEq,Clone,Copy)]
+enum EofMode {
+    Zero,
+    NegOne,
+    Unchanged,
+}
+impl std::str::FromStr for EofMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(EofMode::Zero),
+            "neg-one" => Ok(EofMode::NegOne),
+            "unchanged" => Ok(EofMode::Unchanged),
+            other => Err(format!("unknown --eof-mode {:?} (expected zero, neg-one, or unchanged)", other)),
+        }
+    }
+}
+
+/// bumped whenever the on-disk bytecode layout changes
+const BYTECODE_VERSION: u8 = 1;
+const BYTECODE_MAGIC: &[u8; 4] = b"RBFC";
+
+/// tape growth is rounded up to this many cells at a time, like a heap allocator
+const TAPE_CHUNK: usize = 32 * 1024;
+
+/// The memory tape, parametric over cell width. Arithmetic on a cell wraps
+/// the way it would in any other fixed-width integer (same as the old
+/// `as u8` truncation this replaces, just with a runtime-chosen width).
+enum Tape {
+    Eight(Vec<u8>),
+    Sixteen(Vec<u16>),
+    ThirtyTwo(Vec<u32>),
+}
+impl Tape {
+    fn new(cell_bits: u8, initial_size: usize, max_tape: Option<usize>) -> Result<Self, BfError> {
+        if let Some(max) = max_tape {
+            if initial_size > max {
+                return Err(BfError::TapeLimitExceeded { needed: initial_size, max });
+            }
+        }
+        match cell_bits {
+            8 => Ok(Tape::Eight(vec![0; initial_size])),
+            16 => Ok(Tape::Sixteen(vec![0; initial_size])),
+            32 => Ok(Tape::ThirtyTwo(vec![0; initial_size])),
+            _ => Err(BfError::InvalidCellBits(cell_bits)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Tape::Eight(m) => m.len(),
+            Tape::Sixteen(m) => m.len(),
+            Tape::ThirtyTwo(m) => m.len(),
+        }
+    }
+
+    fn read(&self, ptr: isize) -> isize {
+        let idx = ptr as usize;
+        match self {
+            Tape::Eight(m) => m[idx] as isize,
+            Tape::Sixteen(m) => m[idx] as isize,
+            Tape::ThirtyTwo(m) => m[idx] as isize,
+        }
+    }
+
+    fn write(&mut self, ptr: isize, val: isize) {
+        let idx = ptr as usize;
+        match self {
+            Tape::Eight(m) => m[idx] = val as u8,
+            Tape::Sixteen(m) => m[idx] = val as u16,
+            Tape::ThirtyTwo(m) => m[idx] = val as u32,
+        }
+    }
+
+    /// Grows the tape in `TAPE_CHUNK`-sized steps so that `ptr` is a valid
+    /// index, zero-filling the new cells. Errors out cleanly if `max_tape`
+    /// is set and the tape would have to grow past it.
+    fn grow(&mut self, ptr: isize, max_tape: Option<usize>) -> Result<(), BfError> {
+        if ptr < 0 {
+            return Err(BfError::InvalidAddress(ptr));
+        }
+        let needed = ptr as usize + 1;
+        if needed <= self.len() {
+            return Ok(());
+        }
+        let mut new_len = needed.div_ceil(TAPE_CHUNK) * TAPE_CHUNK;
+        if let Some(max) = max_tape {
+            if needed > max {
+                return Err(BfError::TapeLimitExceeded { needed, max });
+            }
+            new_len = new_len.min(max);
+        }
+        match self {
+            Tape::Eight(m) => m.resize(new_len, 0),
+            Tape::Sixteen(m) => m.resize(new_len, 0),
+            Tape::ThirtyTwo(m) => m.resize(new_len, 0),
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone,Copy)]
 enum BfCommand {
     IncRef,
     IncVal,
     JumpBack,
     JumpTo,
+    MulAdd,
     ScanTo,
     SetVal,
     StdIn,
     StdOut,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy)]
 struct BfInstruction {
     cmd: BfCommand,
     val: isize,
@@ -97,131 +242,266 @@ impl TryFrom<char> for BfInstruction {
     }
 }
 
+/// Aggregate counters handed back from a completed run, for `--debug` output.
+struct Stats {
+    raw_len: usize,
+    compiled_len: usize,
+    count: usize,
+    elapsed: Duration,
+}
+
 fn main() {
+    let args: Args = argh::from_env();
+    let debug = args.debug;
 
-    let mut instructions: Vec<BfInstruction> = Vec::new();
-    let mut jumps: Vec<usize> = Vec::new();
+    match run(args) {
+        Ok(stats) => {
+            println!();
+            if debug {
+                println!("{} raw instructions", stats.raw_len.to_formatted_string(&Locale::en));
+                println!("{} compiled instructions", stats.compiled_len.to_formatted_string(&Locale::en));
+                println!("{} clock cycles executed in {:?}", stats.count.to_formatted_string(&Locale::en), stats.elapsed);
+            }
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
 
-    let args: Args = argh::from_env();
-    let raw_file = fs::read_to_string(args.load).unwrap();
-    let mut raw_bf = replace_all(&raw_file, r"[^+|\-|<|>|\.|,|\[|\]]", "");
-    let raw_len = raw_bf.len();
+fn run(args: Args) -> Result<Stats, BfError> {
 
     let mut input_queue: Vec<char> = Vec::new();
-    if let Some(input_file) = args.input {
-        if let Ok(raw_input) = fs::read_to_string(&input_file) {
-            input_queue.append(&mut raw_input.chars().rev().collect::<Vec<char>>());
-        }
-        else {
-            panic!("Cannot find {}", input_file);
+    if let Some(input_file) = &args.input {
+        let raw_input = fs::read_to_string(input_file).map_err(|_| BfError::FileNotFound(input_file.clone()))?;
+        input_queue.append(&mut raw_input.chars().rev().collect::<Vec<char>>());
+    }
+
+    let raw_file = fs::read_to_string(&args.load).map_err(|_| BfError::FileNotFound(args.load.clone()))?;
+    let source_hash = hash_source(&raw_file);
+
+    if let Some(path) = &args.run_bytecode {
+        let (instructions, raw_len) = load_bytecode(path, args.level, source_hash)?;
+        return execute(&instructions, &args, input_queue, raw_len);
+    }
+
+    // check bracket balance on the source itself so the error carries a byte
+    // offset into the file; the rewrites below only drop balanced `[..]` pairs
+    let mut open_brackets: Vec<usize> = Vec::new();
+    for (byte, c) in raw_file.char_indices() {
+        match c {
+            '[' => open_brackets.push(byte),
+            ']' => { open_brackets.pop().ok_or(BfError::UnbalancedBracket { pos: byte })?; },
+            _ => {},
         }
     }
+    if let Some(pos) = open_brackets.pop() {
+        return Err(BfError::UnbalancedBracket { pos });
+    }
+
+    let mut instructions: Vec<BfInstruction> = Vec::new();
+    let mut jumps: Vec<(usize, usize)> = Vec::new();
+
+    let mut raw_bf = replace_all(&raw_file, r"[^+|\-|<|>|\.|,|\[|\]]", "")?;
+    let raw_len = raw_bf.len();
 
     if args.level > 0 {
-        raw_bf = replace_all(&raw_bf, r"\[\+\]", "=");
-        raw_bf = replace_all(&raw_bf, r"\[\-\]", "=");
-        raw_bf = replace_all2(&raw_bf, r"\[(<{1,27})\]", 'a');
-        raw_bf = replace_all2(&raw_bf, r"\[(>{1,27})\]", 'A');
-    }
-
-    for val in raw_bf.chars() { 
-        if let Ok(mut instruction) = BfInstruction::try_from(val) {
-            let i = instructions.len();
-            if instruction.cmd == BfCommand::JumpBack {
-                let x = jumps.pop().unwrap();
-                instructions[x] = BfInstruction::new(BfCommand::JumpTo, i as isize);
-                instruction.val = x as isize;
-            }
-            else if instruction.cmd == BfCommand::JumpTo {
-                jumps.push(i);
+        raw_bf = replace_all(&raw_bf, r"\[\+\]", "=")?;
+        raw_bf = replace_all(&raw_bf, r"\[\-\]", "=")?;
+        raw_bf = replace_all2(&raw_bf, r"\[(<{1,27})\]", 'a')?;
+        raw_bf = replace_all2(&raw_bf, r"\[(>{1,27})\]", 'A')?;
+    }
+
+    // running pointer delta accumulated by folded `IncRef`s; only used at level > 2
+    let mut ptr_delta: isize = 0;
+
+    for (pos, val) in raw_bf.chars().enumerate() {
+        let mut instruction = BfInstruction::try_from(val).map_err(|_| BfError::UnknownInstruction(val))?;
+
+        if args.level > 2 && instruction.cmd == BfCommand::IncRef {
+            ptr_delta += instruction.val;
+            continue;
+        }
+
+        if args.level > 2 && ptr_delta != 0 {
+            match instruction.cmd {
+                // the cell these test has to be the real one, so flush the
+                // pending move into a real IncRef before testing/scanning it
+                BfCommand::JumpTo | BfCommand::JumpBack | BfCommand::ScanTo => {
+                    instructions.push(BfInstruction::new(BfCommand::IncRef, ptr_delta));
+                    ptr_delta = 0;
+                },
+                // these read/write through an offset, so fold the move into it instead
+                BfCommand::IncVal | BfCommand::SetVal | BfCommand::StdIn | BfCommand::StdOut => {
+                    instruction.offset = ptr_delta;
+                },
+                _ => {},
             }
-            if args.level > 1 {
-                if i > 0 && (instruction.cmd == BfCommand::IncRef || instruction.cmd == BfCommand::IncVal) {
-                    if let Some(last_instruction) = instructions.get_mut(i - 1) {
-                        if last_instruction.cmd == instruction.cmd {
-                            last_instruction.offset += instruction.offset;
-                            last_instruction.val += instruction.val;
-                            continue;
-                        }
-                        // else if args.level > 2 {
-                        //     if last_instruction.cmd == BfCommand::IncRef && instruction.cmd == BfCommand::IncVal {
+        }
 
-                        //     }
-                        // }
+        let i = instructions.len();
+        if instruction.cmd == BfCommand::JumpBack {
+            let (x, _) = jumps.pop().ok_or(BfError::UnbalancedBracket { pos })?;
+            instructions[x] = BfInstruction::new(BfCommand::JumpTo, i as isize);
+            instruction.val = x as isize;
+        }
+        else if instruction.cmd == BfCommand::JumpTo {
+            jumps.push((i, pos));
+        }
+        if args.level > 1 {
+            if i > 0 && (instruction.cmd == BfCommand::IncRef || instruction.cmd == BfCommand::IncVal) {
+                if let Some(last_instruction) = instructions.get_mut(i - 1) {
+                    if last_instruction.cmd == instruction.cmd && last_instruction.offset == instruction.offset {
+                        last_instruction.val += instruction.val;
+                        continue;
                     }
+                    // else if args.level > 2 {
+                    //     if last_instruction.cmd == BfCommand::IncRef && instruction.cmd == BfCommand::IncVal {
+
+                    //     }
+                    // }
                 }
             }
-            instructions.push(instruction);
         }
+        instructions.push(instruction);
     }
 
+    if args.level > 2 && ptr_delta != 0 {
+        instructions.push(BfInstruction::new(BfCommand::IncRef, ptr_delta));
+    }
+
+    if let Some((_, pos)) = jumps.pop() {
+        return Err(BfError::UnbalancedBracket { pos });
+    }
+
+    let instructions = if args.level > 3 {
+        fold_multiply_loops(instructions)
+    }
+    else {
+        instructions
+    };
+
+    if let Some(path) = &args.emit {
+        save_bytecode(path, &instructions, args.level, source_hash, raw_len)?;
+        return Ok(Stats {
+            raw_len,
+            compiled_len: instructions.len(),
+            count: 0,
+            elapsed: Duration::ZERO,
+        });
+    }
+
+    execute(&instructions, &args, input_queue, raw_len)
+}
+
+fn execute(instructions: &[BfInstruction], args: &Args, mut input_queue: Vec<char>, raw_len: usize) -> Result<Stats, BfError> {
+
     if args.verbose {
-        for ins in &instructions {
+        for ins in instructions {
             println!("{:?}", ins);
         }
     }
 
-    let mut memory: Vec<u8> = vec![0; u16::MAX.into()];
+    let mut memory = Tape::new(args.cell_bits, args.tape_size, args.max_tape)?;
     let mut mem_ptr = 0;
     let mut ins_ptr = 0;
     let mut count: usize = 0;
     let ins_len = instructions.len();
     let now = Instant::now();
 
+    let mut breakpoints: HashSet<usize> = HashSet::new();
+    let mut paused = args.interactive;
+
     while ins_ptr < ins_len {
         let ins = &instructions[ins_ptr];
+
+        if args.interactive && (paused || breakpoints.contains(&ins_ptr)) {
+            match run_debugger_repl(ins_ptr, ins, mem_ptr, count, &memory, &mut breakpoints)? {
+                DebugCmd::Step => paused = true,
+                DebugCmd::Continue => paused = false,
+                DebugCmd::Quit => return Ok(Stats {
+                    raw_len,
+                    compiled_len: instructions.len(),
+                    count,
+                    elapsed: Instant::elapsed(&now),
+                }),
+            }
+        }
+
         match ins.cmd {
             BfCommand::IncRef => {
                 mem_ptr += ins.val;
                 if mem_ptr < 0 {
-                    panic!("Invalid Address! {}", mem_ptr);
+                    return Err(BfError::InvalidAddress(mem_ptr));
                 }
             },
             BfCommand::IncVal => {
                 let ptr = mem_ptr + ins.offset;
-                let val = memory[ptr as usize] as isize + ins.val;
-                memory[mem_ptr as usize] = val as u8;
+                memory.grow(ptr, args.max_tape)?;
+                let val = memory.read(ptr) + ins.val;
+                memory.write(ptr, val);
             },
             BfCommand::JumpBack => {
-                if memory[mem_ptr as usize] != 0 {
+                memory.grow(mem_ptr, args.max_tape)?;
+                if memory.read(mem_ptr) != 0 {
                     ins_ptr = ins.val as usize;
                 }
             },
             BfCommand::JumpTo => {
-                if memory[mem_ptr as usize] == 0 {
+                memory.grow(mem_ptr, args.max_tape)?;
+                if memory.read(mem_ptr) == 0 {
                     ins_ptr = ins.val as usize;
                 }
             },
             BfCommand::StdIn => {
-                let mut input = [0; 1];
+                let ptr = mem_ptr + ins.offset;
+                memory.grow(ptr, args.max_tape)?;
                 if input_queue.len() > 0 {
                     let c = input_queue.pop().unwrap();
-                    input[0] = c as u8;
+                    memory.write(ptr, c as isize);
                 }
                 else {
-                    let mut stdin = io::stdin();
-                    stdin.read(&mut input[..]).unwrap();
+                    let mut input = [0; 1];
+                    let n = io::stdin().read(&mut input[..])?;
+                    if n == 0 {
+                        match args.eof_mode {
+                            EofMode::Zero => memory.write(ptr, 0),
+                            EofMode::NegOne => memory.write(ptr, -1),
+                            EofMode::Unchanged => {},
+                        }
+                    } else {
+                        memory.write(ptr, input[0] as isize);
+                    }
                 }
-                let ptr = mem_ptr + ins.offset;
-                memory[ptr as usize] = input[0];
             },
             BfCommand::StdOut => {
                 let ptr = mem_ptr + ins.offset;
-                let val = memory[ptr as usize];
-                let c = val as char;
-                print!("{}", c);
+                memory.grow(ptr, args.max_tape)?;
+                let val = memory.read(ptr);
+                io::stdout().write_all(&[val as u8])?;
                 if args.flush_immediate {
-                    io::stdout().flush().unwrap();
+                    io::stdout().flush()?;
                 }
             },
             BfCommand::SetVal => {
                 let ptr = mem_ptr + ins.offset;
-                memory[ptr as usize] = ins.val as u8;
+                memory.grow(ptr, args.max_tape)?;
+                memory.write(ptr, ins.val);
+            },
+            BfCommand::MulAdd => {
+                let ptr = mem_ptr + ins.offset;
+                memory.grow(ptr, args.max_tape)?;
+                let current = memory.read(mem_ptr);
+                let val = memory.read(ptr) + current * ins.val;
+                memory.write(ptr, val);
             },
             BfCommand::ScanTo => {
                 let mut ptr = 0;
-                while memory[(mem_ptr + ptr) as usize] != 0 {
+                memory.grow(mem_ptr + ptr, args.max_tape)?;
+                while memory.read(mem_ptr + ptr) != 0 {
                     ptr += ins.val;
+                    memory.grow(mem_ptr + ptr, args.max_tape)?;
                 }
                 mem_ptr += ptr;
             },
@@ -232,37 +512,279 @@ fn main() {
 
     }
 
-    println!();
+    Ok(Stats {
+        raw_len,
+        compiled_len: instructions.len(),
+        count,
+        elapsed: Instant::elapsed(&now),
+    })
+}
+
+enum DebugCmd {
+    Step,
+    Continue,
+    Quit,
+}
+
+/// Hashes a script's source so a bytecode file can tell whether it was
+/// compiled from the `.b` file that's currently being loaded.
+fn hash_source(source: &str) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn cmd_to_tag(cmd: BfCommand) -> u8 {
+    match cmd {
+        BfCommand::IncRef => 0,
+        BfCommand::IncVal => 1,
+        BfCommand::JumpBack => 2,
+        BfCommand::JumpTo => 3,
+        BfCommand::MulAdd => 4,
+        BfCommand::ScanTo => 5,
+        BfCommand::SetVal => 6,
+        BfCommand::StdIn => 7,
+        BfCommand::StdOut => 8,
+    }
+}
+
+fn tag_to_cmd(tag: u8) -> Result<BfCommand, BfError> {
+    match tag {
+        0 => Ok(BfCommand::IncRef),
+        1 => Ok(BfCommand::IncVal),
+        2 => Ok(BfCommand::JumpBack),
+        3 => Ok(BfCommand::JumpTo),
+        4 => Ok(BfCommand::MulAdd),
+        5 => Ok(BfCommand::ScanTo),
+        6 => Ok(BfCommand::SetVal),
+        7 => Ok(BfCommand::StdIn),
+        8 => Ok(BfCommand::StdOut),
+        _ => Err(BfError::BytecodeInvalid(format!("unknown instruction tag {}", tag))),
+    }
+}
+
+/// Serializes the compiled instruction stream to a compact binary file: a
+/// small header (magic, format version, optimization level, source hash,
+/// raw instruction count, compiled instruction count) followed by one
+/// `[tag, val, offset]` record per instruction.
+fn save_bytecode(path: &str, instructions: &[BfInstruction], level: usize, source_hash: u64, raw_len: usize) -> Result<(), BfError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(BYTECODE_MAGIC);
+    bytes.push(BYTECODE_VERSION);
+    bytes.extend_from_slice(&(level as u64).to_le_bytes());
+    bytes.extend_from_slice(&source_hash.to_le_bytes());
+    bytes.extend_from_slice(&(raw_len as u64).to_le_bytes());
+    bytes.extend_from_slice(&(instructions.len() as u64).to_le_bytes());
+    for ins in instructions {
+        bytes.push(cmd_to_tag(ins.cmd));
+        bytes.extend_from_slice(&(ins.val as i64).to_le_bytes());
+        bytes.extend_from_slice(&(ins.offset as i64).to_le_bytes());
+    }
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+fn read_u8(bytes: &[u8], pos: &mut usize) -> Result<u8, BfError> {
+    let byte = *bytes.get(*pos).ok_or_else(|| BfError::BytecodeInvalid("unexpected end of file".into()))?;
+    *pos += 1;
+    Ok(byte)
+}
+
+fn read_u64(bytes: &[u8], pos: &mut usize) -> Result<u64, BfError> {
+    let slice = bytes.get(*pos..*pos + 8).ok_or_else(|| BfError::BytecodeInvalid("unexpected end of file".into()))?;
+    *pos += 8;
+    Ok(u64::from_le_bytes(slice.try_into().unwrap()))
+}
+
+fn read_i64(bytes: &[u8], pos: &mut usize) -> Result<i64, BfError> {
+    let slice = bytes.get(*pos..*pos + 8).ok_or_else(|| BfError::BytecodeInvalid("unexpected end of file".into()))?;
+    *pos += 8;
+    Ok(i64::from_le_bytes(slice.try_into().unwrap()))
+}
+
+/// Loads a file written by [`save_bytecode`], rejecting it if its header
+/// doesn't match the requested optimization level or the current source
+/// (a stale artifact from a since-edited script). Returns the instructions
+/// plus the original raw instruction count, for `--debug` stats.
+fn load_bytecode(path: &str, expected_level: usize, expected_source_hash: u64) -> Result<(Vec<BfInstruction>, usize), BfError> {
+    let bytes = fs::read(path).map_err(|_| BfError::FileNotFound(path.to_string()))?;
+    let mut pos = 0usize;
+
+    let magic = bytes.get(0..4).ok_or_else(|| BfError::BytecodeInvalid("truncated header".into()))?;
+    if magic != BYTECODE_MAGIC {
+        return Err(BfError::BytecodeInvalid("not an rbf bytecode file".into()));
+    }
+    pos += 4;
+
+    let version = read_u8(&bytes, &mut pos)?;
+    if version != BYTECODE_VERSION {
+        return Err(BfError::BytecodeInvalid(format!("unsupported bytecode version {}", version)));
+    }
+
+    let level = read_u64(&bytes, &mut pos)? as usize;
+    if level != expected_level {
+        return Err(BfError::BytecodeLevelMismatch { expected: expected_level, found: level });
+    }
+
+    let source_hash = read_u64(&bytes, &mut pos)?;
+    if source_hash != expected_source_hash {
+        return Err(BfError::BytecodeStaleSource);
+    }
+
+    let raw_len = read_u64(&bytes, &mut pos)? as usize;
+    let count = read_u64(&bytes, &mut pos)? as usize;
+
+    let mut instructions = Vec::with_capacity(count);
+    for _ in 0..count {
+        let tag = read_u8(&bytes, &mut pos)?;
+        let val = read_i64(&bytes, &mut pos)? as isize;
+        let offset = read_i64(&bytes, &mut pos)? as isize;
+        instructions.push(BfInstruction { cmd: tag_to_cmd(tag)?, val, offset });
+    }
+
+    Ok((instructions, raw_len))
+}
+
+/// Drives the `--interactive` monitor: reprints the current instruction (the
+/// same dump `--verbose` uses) and blocks for a command until the caller is
+/// told to step, continue, or quit. Jump targets are already precomputed into
+/// `val`, so breakpoints are just indices checked against a `HashSet`.
+fn run_debugger_repl(
+    ins_ptr: usize,
+    ins: &BfInstruction,
+    mem_ptr: isize,
+    count: usize,
+    memory: &Tape,
+    breakpoints: &mut HashSet<usize>,
+) -> Result<DebugCmd, BfError> {
+    loop {
+        println!("[{}] {:?}", ins_ptr, ins);
+        print!("(dbg) ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+
+        match parts.next() {
+            Some("s") => return Ok(DebugCmd::Step),
+            Some("c") => return Ok(DebugCmd::Continue),
+            Some("b") => {
+                match parts.next().and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) => {
+                        breakpoints.insert(n);
+                        println!("breakpoint set at instruction {}", n);
+                    },
+                    None => println!("usage: b <ins_index>"),
+                }
+            },
+            Some("p") => {
+                let addr = parts.next().and_then(|a| a.parse::<isize>().ok()).unwrap_or(mem_ptr);
+                let len = parts.next().and_then(|l| l.parse::<usize>().ok()).unwrap_or(16);
+                dump_memory(memory, addr, len);
+            },
+            Some("r") => println!("mem_ptr={} ins_ptr={} count={}", mem_ptr, ins_ptr, count),
+            Some("q") | None => return Ok(DebugCmd::Quit),
+            _ => println!("commands: s, c, b <ins_index>, p <addr> [len], r, q"),
+        }
+    }
+}
+
+/// Dumps `len` tape cells starting at `addr`, clamped to the allocated tape.
+fn dump_memory(memory: &Tape, addr: isize, len: usize) {
+    let start = addr.max(0) as usize;
+    let end = (start + len).min(memory.len());
+    for i in start..end {
+        println!("{:>6}: {:3}", i, memory.read(i as isize));
+    }
+}
+
+/// Replaces `[->+>+++<<]`-style multiply/copy loops with a constant-time
+/// sequence of `MulAdd`s plus a trailing `SetVal 0`, the way the level-1 pass
+/// already collapses `[+]`/`[-]` into `SetVal`. A loop qualifies when its body
+/// is loop-free, touches no I/O, has zero net pointer movement, and
+/// decrements the current cell exactly once per iteration; anything else
+/// (including the non-decrementing, infinite-loop case) is left untouched.
+fn fold_multiply_loops(instructions: Vec<BfInstruction>) -> Vec<BfInstruction> {
+    let len = instructions.len();
+    let mut old_to_new: Vec<Option<usize>> = vec![None; len];
+    let mut folded: Vec<BfInstruction> = Vec::with_capacity(len);
 
-    if args.debug {
-        let elapsed = Instant::elapsed(&now);
-        println!("{} raw instructions", raw_len.to_formatted_string(&Locale::en));
-        println!("{} compiled instructions", instructions.len().to_formatted_string(&Locale::en));
-        println!("{} clock cycles executed in {:?}", count.to_formatted_string(&Locale::en), elapsed);
+    let mut i = 0;
+    while i < len {
+        let ins = instructions[i];
+        if ins.cmd == BfCommand::JumpTo {
+            let e = ins.val as usize;
+            if let Some(replacement) = linear_loop_replacement(&instructions[i + 1..e]) {
+                folded.extend(replacement);
+                i = e + 1;
+                continue;
+            }
+        }
+        old_to_new[i] = Some(folded.len());
+        folded.push(ins);
+        i += 1;
+    }
+
+    for ins in &mut folded {
+        if ins.cmd == BfCommand::JumpTo || ins.cmd == BfCommand::JumpBack {
+            ins.val = old_to_new[ins.val as usize].expect("jump target was folded away") as isize;
+        }
     }
 
+    folded
+}
+
+/// Returns the `MulAdd`/`SetVal` replacement for a linear loop body, or `None`
+/// if the body isn't a linear loop (I/O, a nested loop, non-zero net pointer
+/// movement, or a current-cell delta other than exactly -1 per iteration).
+fn linear_loop_replacement(body: &[BfInstruction]) -> Option<Vec<BfInstruction>> {
+    if body.is_empty() || body.iter().any(|ins| ins.cmd != BfCommand::IncVal) {
+        return None;
+    }
+
+    let mut deltas: Vec<(isize, isize)> = Vec::new();
+    for ins in body {
+        match deltas.iter_mut().find(|(offset, _)| *offset == ins.offset) {
+            Some((_, factor)) => *factor += ins.val,
+            None => deltas.push((ins.offset, ins.val)),
+        }
+    }
+    deltas.sort_by_key(|(offset, _)| *offset);
+
+    let current = deltas.iter().find(|(offset, _)| *offset == 0).map(|(_, f)| *f).unwrap_or(0);
+    if current != -1 {
+        return None;
+    }
+
+    let mut replacement: Vec<BfInstruction> = deltas
+        .into_iter()
+        .filter(|(offset, _)| *offset != 0)
+        .map(|(offset, factor)| BfInstruction { cmd: BfCommand::MulAdd, val: factor, offset })
+        .collect();
+    replacement.push(BfInstruction::new(BfCommand::SetVal, 0));
+    Some(replacement)
 }
 
 fn replace_all(
     text: &str,
     exp: &str,
     rep: &str,
-) -> String {
-    Regex::new(&exp)
-        .unwrap()
+) -> Result<String, BfError> {
+    Ok(Regex::new(exp)?
         .replace_all(text, rep)
-        .to_string()
+        .to_string())
 }
 
 fn replace_all2(
     text: &str,
     exp: &str,
     starter: char,
-) -> String {
-    Regex::new(&exp)
-        .unwrap()
+) -> Result<String, BfError> {
+    Ok(Regex::new(exp)?
         .replace_all(text, |caps: &Captures| {
             ((starter as u8 + caps.get(1).unwrap().as_str().len() as u8) as char).to_string()
         })
-        .to_string()
-}
\ No newline at end of file
+        .to_string())
+}